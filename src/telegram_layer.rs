@@ -1,13 +1,21 @@
 use chrono::Local;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use teloxide::prelude::*;
+use teloxide::utils::command::BotCommands;
 use tokio::sync::mpsc;
 use tracing::field::{Field, Visit};
 use tracing::{Event, Subscriber};
 
 use tracing_subscriber::Layer;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::layer::Context;
 
 /// 日志输出格式
@@ -19,49 +27,438 @@ pub enum TelegramFormat {
     Template(&'static str),
 }
 
+/// 一条待发送消息，落盘时会被序列化为一行 JSON
+#[derive(Clone)]
+struct QueuedMessage {
+    msg: String,
+    parse_mode: Option<teloxide::types::ParseMode>,
+    chat_ids: Vec<i64>,
+}
+
+/// `QueuedMessage` 落盘时的行格式，teloxide 的 `ParseMode` 未实现 `Deserialize`，
+/// 所以这里只落盘一个简单的标签，读回时再映射回枚举
+#[derive(serde::Serialize, serde::Deserialize)]
+struct WalEntry {
+    msg: String,
+    parse_mode: Option<String>,
+    chat_ids: Vec<i64>,
+}
+
+fn parse_mode_tag(parse_mode: &Option<teloxide::types::ParseMode>) -> Option<String> {
+    use teloxide::types::ParseMode;
+    match parse_mode {
+        Some(ParseMode::MarkdownV2) => Some("MarkdownV2".to_string()),
+        Some(ParseMode::Markdown) => Some("Markdown".to_string()),
+        Some(ParseMode::Html) => Some("Html".to_string()),
+        None => None,
+    }
+}
+
+fn parse_mode_from_tag(tag: Option<String>) -> Option<teloxide::types::ParseMode> {
+    use teloxide::types::ParseMode;
+    match tag.as_deref() {
+        Some("MarkdownV2") => Some(ParseMode::MarkdownV2),
+        Some("Markdown") => Some(ParseMode::Markdown),
+        Some("Html") => Some(ParseMode::Html),
+        _ => None,
+    }
+}
+
+/// 从磁盘上的预写日志文件中重放出尚未发送的消息，文件不存在时视为空队列
+fn load_wal(path: &Path) -> VecDeque<QueuedMessage> {
+    let Ok(file) = std::fs::File::open(path) else {
+        return VecDeque::new();
+    };
+    std::io::BufReader::new(file)
+        .lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<WalEntry>(&line).ok())
+        .map(|entry| QueuedMessage {
+            msg: entry.msg,
+            parse_mode: parse_mode_from_tag(entry.parse_mode),
+            chat_ids: entry.chat_ids,
+        })
+        .collect()
+}
+
+/// 追加一条消息到预写日志，失败时只打印错误，不影响发送流程
+fn append_wal(path: &Path, entry: &QueuedMessage) {
+    let wal_entry = WalEntry {
+        msg: entry.msg.clone(),
+        parse_mode: parse_mode_tag(&entry.parse_mode),
+        chat_ids: entry.chat_ids.clone(),
+    };
+    match serde_json::to_string(&wal_entry) {
+        Ok(json) => {
+            if let Ok(mut file) = std::fs::OpenOptions::new().create(true).append(true).open(path) {
+                if let Err(err) = writeln!(file, "{}", json) {
+                    eprintln!("Failed to append to Telegram WAL file: {}", err);
+                }
+            }
+        }
+        Err(err) => eprintln!("Failed to serialize Telegram WAL entry: {}", err),
+    }
+}
+
+/// 将剩余队列整体重写回预写日志，用于已投递消息出队后压缩文件
+fn rewrite_wal(path: &Path, pending: &VecDeque<QueuedMessage>) {
+    let Ok(mut file) = std::fs::File::create(path) else {
+        return;
+    };
+    for entry in pending {
+        let wal_entry = WalEntry {
+            msg: entry.msg.clone(),
+            parse_mode: parse_mode_tag(&entry.parse_mode),
+            chat_ids: entry.chat_ids.clone(),
+        };
+        if let Ok(json) = serde_json::to_string(&wal_entry) {
+            let _ = writeln!(file, "{}", json);
+        }
+    }
+}
+
+/// 单条消息合并进一批后允许的最大长度，预留一些余量给包裹用的代码块标记
+const MAX_BATCH_LEN: usize = 4000;
+
+/// 同一批消息连续投递失败达到这个次数后放弃重试，避免一条有问题的消息
+/// （chat id 不存在、被用户拉黑、实体格式非法等）永久堵死后面的队列
+const MAX_DELIVERY_ATTEMPTS: u32 = 10;
+
+/// 根据 parse_mode 为拼接后的一批消息包一层代码块，使其在 MarkdownV2 下整体渲染为一条消息
+fn wrap_for_send(text: &str, parse_mode: Option<teloxide::types::ParseMode>) -> String {
+    match parse_mode {
+        Some(teloxide::types::ParseMode::MarkdownV2) => format!("```\n{}\n```", text),
+        _ => text.to_string(),
+    }
+}
+
+/// 一批消息对一组 chat_id 的投递结果
+struct DeliveryResult {
+    /// 仍未成功投递、需要重试的 chat_id 子集；已经成功的 chat 不会再出现在这里，
+    /// 避免重试时给已经收到过的 chat 再发一遍
+    remaining: Vec<i64>,
+    /// 本轮是否遇到了 429，遇到时已经按 Telegram 告知的秒数睡眠过，
+    /// 调用方应针对 `remaining` 立即重试而不再额外退避
+    retry_now: bool,
+}
+
+async fn deliver_batch(bot: &Bot, batch: &[QueuedMessage], chat_ids: &[i64]) -> DeliveryResult {
+    let Some(first) = batch.first() else {
+        return DeliveryResult {
+            remaining: Vec::new(),
+            retry_now: false,
+        };
+    };
+    let parse_mode = first.parse_mode;
+    let joined = batch
+        .iter()
+        .map(|entry| entry.msg.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = wrap_for_send(&joined, parse_mode);
+
+    let mut remaining = Vec::new();
+    let mut retry_now = false;
+    for chat_id in chat_ids {
+        let mut req = bot.send_message(ChatId(*chat_id), text.clone());
+        if let Some(pm) = parse_mode {
+            req = req.parse_mode(pm);
+        }
+        match req.await {
+            Ok(_) => {}
+            Err(teloxide::RequestError::RetryAfter(retry_after)) => {
+                tokio::time::sleep(retry_after.duration()).await;
+                retry_now = true;
+                remaining.push(*chat_id);
+            }
+            Err(_) => remaining.push(*chat_id),
+        }
+    }
+    DeliveryResult {
+        remaining,
+        retry_now,
+    }
+}
+
+fn hash_message(msg: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    msg.hash(&mut hasher);
+    hasher.finish()
+}
+
+enum DedupeDecision {
+    /// 与抑制窗口内的上一条消息相同，丢弃，不发送
+    Suppress,
+    /// 应当发送当前消息；如果之前有消息被抑制过，先发送一条汇总行
+    Send(Option<String>),
+}
+
+fn repeated_summary(count: u32, window: Duration) -> String {
+    format!("⚠️ (last message repeated {}× in {}s)", count, window.as_secs())
+}
+
+/// 重复/刷屏抑制：同一条消息在 `window` 内重复出现时被丢弃，只计数，
+/// 窗口过期后该消息再次出现、或 `sweep_expired` 清理到它时，补发一条「重复了 N 次」的汇总；
+/// 出现一条不同的消息并不会触发上一条消息的汇总。被抑制的条目还记录
+/// 最后一次出现时的 chat_ids，供 `sweep_expired` 在消息不再出现时仍能补发汇总
+struct Dedupe {
+    window: Duration,
+    seen: Mutex<HashMap<u64, (Instant, u32, Vec<i64>)>>,
+}
+
+impl Dedupe {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, msg: &str, chat_ids: &[i64]) -> DedupeDecision {
+        let hash = hash_message(msg);
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+
+        if let Some((since, count, seen_chat_ids)) = seen.get_mut(&hash) {
+            *seen_chat_ids = chat_ids.to_vec();
+            if now.duration_since(*since) < self.window {
+                *count += 1;
+                return DedupeDecision::Suppress;
+            }
+
+            let dropped = *count;
+            *since = now;
+            *count = 0;
+            return DedupeDecision::Send(if dropped > 0 {
+                Some(repeated_summary(dropped, self.window))
+            } else {
+                None
+            });
+        }
+
+        seen.insert(hash, (now, 0, chat_ids.to_vec()));
+        DedupeDecision::Send(None)
+    }
+
+    /// 定期调用：取出已经超出抑制窗口、但因为消息再也没有出现过而一直没有机会
+    /// 补发「重复了 N 次」汇总的条目，连同它们最后一次出现时的 chat_ids 一起返回，
+    /// 让调用方照常投递。没有被抑制过（count 为 0）的条目直接清理，不产生汇总
+    fn sweep_expired(&self) -> Vec<(String, Vec<i64>)> {
+        let now = Instant::now();
+        let window = self.window;
+        let mut seen = self.seen.lock().unwrap();
+        let mut flushed = Vec::new();
+        seen.retain(|_, (since, count, chat_ids)| {
+            let expired = now.duration_since(*since) >= window;
+            if expired && *count > 0 {
+                flushed.push((repeated_summary(*count, window), chat_ids.clone()));
+            }
+            !expired
+        });
+        flushed
+    }
+}
+
 /// 队列异步发送器
 #[derive(Clone)]
 struct TelegramSender {
-    sender: mpsc::Sender<(String, Option<teloxide::types::ParseMode>)>,
+    sender: mpsc::Sender<QueuedMessage>,
+    wal_path: Option<PathBuf>,
 }
 
 impl TelegramSender {
-    pub fn new(bot: Arc<Bot>, chat_ids: Vec<i64>) -> Self {
-        //   let (ftx,frx ) = futures::channel::mpsc::channel::<(String,Option<teloxide::types::ParseMode>)>(100);
-        let (tx, mut rx) = mpsc::channel::<(String, Option<teloxide::types::ParseMode>)>(100);
-        let bot_clone = bot.clone();
-     
-        tokio::spawn(async move {
-            while let Some((msg, parse_mode)) = rx.recv().await {
-                 let chat_id = chat_ids.clone();
-                for chat_id in chat_id {
-                       let mut req = bot_clone.send_message(ChatId(chat_id), msg.to_owned());
-                if let Some(pm) = parse_mode {
-                    req = req.parse_mode(pm);
-                }
-                if let Err(_) = req.await {
-                    // eprintln!("Failed to send log to Telegram: {}", err);
-                    tokio::time::sleep(Duration::from_secs(60)).await; // 等待60秒后重试
-                }
+    /// `wal_path` 为 `Some` 时启用崩溃安全队列：进入 channel 前先追加写入该文件，
+    /// 启动时重放文件中尚未发送的消息，失败的消息会按指数退避原地重试；同一批连续失败
+    /// `MAX_DELIVERY_ATTEMPTS` 次后该批会从队列和文件中移除，不再投递。
+    /// `batch_window` 为 `Some` 时启用批量发送：在该时间窗口内到达的消息会被合并成一条
+    pub fn new(bot: Arc<Bot>, wal_path: Option<PathBuf>, batch_window: Option<Duration>) -> Self {
+        let (tx, rx) = mpsc::channel::<QueuedMessage>(100);
+
+        tokio::spawn(Self::run(bot, wal_path.clone(), batch_window, rx));
+
+        Self {
+            sender: tx,
+            wal_path,
+        }
+    }
+
+    /// `chat_ids` 是这条消息的投递目标，由调用方根据路由规则算出。落盘先于入队，
+    /// 这样即便 worker 正卡在某一批的重试退避里，消息也已经是崩溃安全的
+    async fn send(
+        &self,
+        msg: String,
+        parse_mode: Option<teloxide::types::ParseMode>,
+        chat_ids: Vec<i64>,
+    ) {
+        let entry = QueuedMessage {
+            msg,
+            parse_mode,
+            chat_ids,
+        };
+        if let Some(path) = &self.wal_path {
+            append_wal(path, &entry);
+        }
+        let _ = self.sender.send(entry).await;
+    }
+
+    /// 从队首取出一批待发送消息。未配置 `batch_window` 时只取队首一条，行为与
+    /// 未启用批量发送完全一致；配置了的话才会先攒满当前已在队列中的同路由内容，
+    /// 队列因此取空时再在窗口允许的时间里继续等待新消息加入，直到超过长度上限或时间窗口耗尽
+    async fn take_batch(
+        pending: &mut VecDeque<QueuedMessage>,
+        batch_window: Option<Duration>,
+        rx: &mut mpsc::Receiver<QueuedMessage>,
+    ) -> Vec<QueuedMessage> {
+        let mut batch: Vec<QueuedMessage> = Vec::new();
+
+        let Some(window) = batch_window else {
+            if let Some(entry) = pending.pop_front() {
+                batch.push(entry);
+            }
+            return batch;
+        };
+
+        let mut len = 0usize;
+        while let Some(front) = pending.front() {
+            if !batch.is_empty()
+                && (front.chat_ids != batch[0].chat_ids
+                    || parse_mode_tag(&front.parse_mode) != parse_mode_tag(&batch[0].parse_mode)
+                    || len + front.msg.len() + 1 > MAX_BATCH_LEN)
+            {
+                break;
+            }
+            len += front.msg.len() + 1;
+            batch.push(pending.pop_front().unwrap());
+        }
+
+        // 队列里还有更早到达、但并不进本批的消息时不再等待新消息，否则新消息会越过它们先发出
+        if !pending.is_empty() {
+            return batch;
+        }
+
+        let deadline = tokio::time::sleep(window);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                received = rx.recv() => {
+                    match received {
+                        Some(entry) => {
+                            let same_route = batch.first().map_or(true, |first| {
+                                first.chat_ids == entry.chat_ids
+                                    && parse_mode_tag(&first.parse_mode) == parse_mode_tag(&entry.parse_mode)
+                            });
+                            if !batch.is_empty() && (!same_route || len + entry.msg.len() + 1 > MAX_BATCH_LEN) {
+                                pending.push_front(entry);
+                                break;
+                            }
+                            len += entry.msg.len() + 1;
+                            batch.push(entry);
+                        }
+                        None => break,
+                    }
                 }
             }
-        });
+        }
 
-        Self { sender: tx }
+        batch
     }
 
-    async fn send(&self, msg: String, parse_mode: Option<teloxide::types::ParseMode>) {
-        let _ = self.sender.send((msg, parse_mode)).await;
+    async fn run(
+        bot: Arc<Bot>,
+        wal_path: Option<PathBuf>,
+        batch_window: Option<Duration>,
+        mut rx: mpsc::Receiver<QueuedMessage>,
+    ) {
+        let mut pending: VecDeque<QueuedMessage> = wal_path
+            .as_deref()
+            .map(load_wal)
+            .unwrap_or_default();
+
+        loop {
+            while let Ok(entry) = rx.try_recv() {
+                pending.push_back(entry);
+            }
+
+            if pending.is_empty() {
+                match rx.recv().await {
+                    Some(entry) => pending.push_back(entry),
+                    None => return,
+                }
+                continue;
+            }
+
+            // 一批一起原地重试，以保证同一 chat 内的顺序
+            let batch = Self::take_batch(&mut pending, batch_window, &mut rx).await;
+            let Some(mut remaining_chat_ids) = batch.first().map(|first| first.chat_ids.clone())
+            else {
+                continue;
+            };
+            let mut attempt: u32 = 0;
+            loop {
+                let result = deliver_batch(&bot, &batch, &remaining_chat_ids).await;
+                if result.remaining.is_empty() {
+                    if let Some(path) = &wal_path {
+                        // 仍在 channel 里的消息已经落盘，先收进 pending，重写时才不会被抹掉
+                        while let Ok(entry) = rx.try_recv() {
+                            pending.push_back(entry);
+                        }
+                        rewrite_wal(path, &pending);
+                    }
+                    break;
+                }
+                remaining_chat_ids = result.remaining;
+
+                if result.retry_now {
+                    attempt = 0;
+                    continue;
+                }
+
+                attempt += 1;
+                if attempt >= MAX_DELIVERY_ATTEMPTS {
+                    eprintln!(
+                        "Telegram 消息投递连续失败 {} 次，放弃向剩余 chat {:?} 投递该批次并继续后续队列",
+                        attempt, remaining_chat_ids
+                    );
+                    if let Some(path) = &wal_path {
+                        while let Ok(entry) = rx.try_recv() {
+                            pending.push_back(entry);
+                        }
+                        rewrite_wal(path, &pending);
+                    }
+                    break;
+                }
+                let backoff = Duration::from_secs(1u64 << (attempt - 1).min(6))
+                    .min(Duration::from_secs(60));
+                tokio::time::sleep(backoff).await;
+            }
+        }
     }
 }
 
+/// 运行时日志环形缓冲区容量，供 `/tail` 命令回放
+const RING_CAPACITY: usize = 500;
+
 /// Telegram Layer
 #[derive(Clone)]
 pub struct TelegramLayer {
     sender: TelegramSender,
     format: TelegramFormat,
-    tag: Vec<String>,
+    /// 允许通过 `/tag add|remove` 运行时修改，因此需要共享可变状态
+    tag: Arc<Mutex<Vec<String>>>,
     unknown: String,
+    /// 没有命中 `routes` 时的默认投递目标
+    default_chat_ids: Vec<i64>,
+    /// 按日志级别路由到不同 chat，未命中的级别回退到 `default_chat_ids`
+    routes: HashMap<Level, Vec<i64>>,
+    /// 设置后启用重复消息抑制
+    dedupe: Option<Arc<Dedupe>>,
+    /// 运行时可通过 `/level` 命令调整的最低转发级别
+    level_filter: Arc<ArcSwap<LevelFilter>>,
+    /// 设置后，在此之前到达的事件只记录到环形缓冲区而不发送
+    muted_until: Arc<Mutex<Option<Instant>>>,
+    /// 最近格式化过的日志行（包括因静音或去重而未发送的），供 `/tail` 命令回放
+    ring: Arc<Mutex<VecDeque<String>>>,
 }
 
 
@@ -71,17 +468,80 @@ impl TelegramLayer {
     }
 }
 
-/// 提取 event message
+/// 提取 event 的 message 字段与其余结构化字段，按记录顺序保留
 struct MessageVisitor {
-    output: String,
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl MessageVisitor {
+    fn new() -> Self {
+        Self {
+            message: String::new(),
+            fields: Vec::new(),
+        }
+    }
 }
 
 impl Visit for MessageVisitor {
     fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
         if field.name() == "message" {
-            self.output.push_str(&format!("{:?}", value));
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.push((field.name().to_string(), value.to_string()));
+    }
+}
+
+/// 格式化成 `key=value` 空格分隔的字符串，供 Text/Markdown 输出追加字段
+fn format_fields(fields: &[(String, String)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 记录在某个 span 上的字段，在 `on_new_span` 时写入该 span 的 extensions
+struct SpanFields(Vec<(String, String)>);
+
+/// 沿 `ctx.event_scope()` 从根到叶收集 span 名称链路，以及每个 span 上记录的字段
+fn collect_spans<S>(ctx: &Context<'_, S>, event: &Event<'_>) -> (Vec<String>, Vec<(String, String)>)
+where
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+{
+    let mut names = Vec::new();
+    let mut fields = Vec::new();
+    if let Some(scope) = ctx.event_scope(event) {
+        for span in scope.from_root() {
+            names.push(span.name().to_string());
+            if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                fields.extend(span_fields.0.clone());
+            }
         }
     }
+    (names, fields)
 }
 
 use std::collections::HashMap;
@@ -105,71 +565,124 @@ lazy_static! {
 
 impl<S> Layer<S> for TelegramLayer
 where
-    S: Subscriber,
+    S: Subscriber + for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
 {
-    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
-        let mut visitor = MessageVisitor {
-            output: String::new(),
-        };
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("span must exist in on_new_span");
+        let mut visitor = MessageVisitor::new();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.fields));
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::new();
         event.record(&mut visitor);
 
-        if visitor.output.is_empty() {
+        if visitor.message.is_empty() {
+            return;
+        }
+
+        let meta = event.metadata();
+        let level = meta.level();
+        // 运行时可通过 /level 命令调整的最低转发级别
+        if !(*self.level_filter.load().as_ref() >= *level) {
             return;
         }
         // 允许tag 标记日志
-        if self.tag.len() > 0 {
-            let mut flag = false;
-            for tag in &self.tag {
-                if visitor.output.contains(tag) {
-                    flag = true;
-                    break;
+        {
+            let tag = self.tag.lock().unwrap();
+            if tag.len() > 0 {
+                let mut flag = false;
+                for t in tag.iter() {
+                    if visitor.message.contains(t) {
+                        flag = true;
+                        break;
+                    }
+                }
+                if !flag {
+                    return;
                 }
-            }
-            if !flag {
-                return;
             }
         }
 
-        let meta = event.metadata();
         let line = meta.line();
         let file = meta.file();
         let module = meta.module_path();
-        let level = meta.level();
         let now = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
         let emoji = LEVEL_EMOJIS.get(&level).copied().unwrap_or(&self.unknown);
+        let chat_ids = self
+            .routes
+            .get(level)
+            .cloned()
+            .unwrap_or_else(|| self.default_chat_ids.clone());
+        let (span_names, span_fields) = collect_spans(&ctx, event);
+        let mut all_fields = visitor.fields.clone();
+        all_fields.extend(span_fields);
 
         let (msg, parse_mode) = match self.format {
-            TelegramFormat::Text => (format!("{} [{}] {}", emoji, now, visitor.output), None),
+            TelegramFormat::Text => {
+                        let mut line = format!("{} [{}] {}", emoji, now, visitor.message);
+                        if !all_fields.is_empty() {
+                            line.push(' ');
+                            line.push_str(&format_fields(&all_fields));
+                        }
+                        if !span_names.is_empty() {
+                            line.push_str(&format!(" spans: {}", span_names.join(">")));
+                        }
+                        (line, None)
+                    }
             TelegramFormat::Markdown => {
-                        let escaped_output = escape_markdown_v2(&visitor.output);
+                        let escaped_output = escape_markdown_v2(&visitor.message);
                         let file = file.unwrap_or(&self.unknown).replace("\\", "/");
                         let line = line.unwrap_or(0);
                         let module = module.unwrap_or(&self.unknown);
-                        (
-                            format!(
-                                "```\n{emoji} [{}] {}:{} {} {} [{level}]\n```",
-                                now, module,line, file, escaped_output
-                            ),
-                            Some(teloxide::types::ParseMode::MarkdownV2),
-                        )
+                        let mut text = format!(
+                            "{emoji} [{}] {}:{} {} {} [{level}]",
+                            now, module,line, file, escaped_output
+                        );
+                        if !all_fields.is_empty() {
+                            text.push(' ');
+                            text.push_str(&escape_markdown_v2(&format_fields(&all_fields)));
+                        }
+                        if !span_names.is_empty() {
+                            text.push_str(&format!(
+                                " spans: {}",
+                                escape_markdown_v2(&span_names.join(">"))
+                            ));
+                        }
+                        (text, Some(teloxide::types::ParseMode::MarkdownV2))
                     }
             TelegramFormat::Json => {
-                        let json = format!(
-                            r#"``` {{"time": "{}", "emoji": "{}", "msg": "{}", "level": "{}", "module": "{}", "file": "{}", "line": {} }} ```"#,
-                            now,
-                            emoji,
-                            visitor.output,
-                            level,
-                            module.unwrap_or(&self.unknown),
-                            file.unwrap_or(&self.unknown).replace("\\", "/"),
-                            line.unwrap_or(0)
+                        let mut map = serde_json::Map::new();
+                        map.insert("time".to_string(), serde_json::Value::String(now.clone()));
+                        map.insert("emoji".to_string(), serde_json::Value::String(emoji.to_string()));
+                        map.insert("msg".to_string(), serde_json::Value::String(visitor.message.clone()));
+                        map.insert("level".to_string(), serde_json::Value::String(level.to_string()));
+                        map.insert(
+                            "module".to_string(),
+                            serde_json::Value::String(module.unwrap_or(&self.unknown).to_string()),
+                        );
+                        map.insert(
+                            "file".to_string(),
+                            serde_json::Value::String(file.unwrap_or(&self.unknown).replace("\\", "/")),
                         );
+                        map.insert("line".to_string(), serde_json::Value::from(line.unwrap_or(0)));
+                        for (key, value) in &all_fields {
+                            map.insert(key.clone(), serde_json::Value::String(value.clone()));
+                        }
+                        if !span_names.is_empty() {
+                            map.insert(
+                                "spans".to_string(),
+                                serde_json::Value::String(span_names.join(">")),
+                            );
+                        }
+                        let json = serde_json::to_string(&map).unwrap_or_default();
                         (json, Some(teloxide::types::ParseMode::MarkdownV2))
                     }
                 TelegramFormat::Template(tpl) => {
                     let tpl = tpl.replace("{emoji}", emoji)
                         .replace("{time}", &now)
-                        .replace("{msg}", &visitor.output)
+                        .replace("{msg}", &visitor.message)
                         .replace("{level}", &level.to_string())
                         .replace("{module}", module.unwrap_or(&self.unknown))
                         .replace("{file}", &file.unwrap_or(&self.unknown).replace("\\", "/"))
@@ -179,13 +692,160 @@ where
                 ,
         };
 
+        {
+            let mut ring = self.ring.lock().unwrap();
+            ring.push_back(msg.clone());
+            if ring.len() > RING_CAPACITY {
+                ring.pop_front();
+            }
+        }
+
+        let summary = if let Some(dedupe) = &self.dedupe {
+            // 去重键只取消息正文和字段，不含 `now` 等每次都会变化的内容，
+            // 否则同一条错误哪怕只隔一秒重复出现也会被当成不同消息
+            let dedupe_key = format!("{}{}", visitor.message, format_fields(&all_fields));
+            match dedupe.check(&dedupe_key, &chat_ids) {
+                DedupeDecision::Suppress => return,
+                DedupeDecision::Send(summary) => summary,
+            }
+        } else {
+            None
+        };
+
+        let muted = self
+            .muted_until
+            .lock()
+            .unwrap()
+            .map_or(false, |until| Instant::now() < until);
+        if muted {
+            return;
+        }
+
         let sender = self.sender.clone();
         tokio::spawn(async move {
-            sender.send(msg, parse_mode).await;
+            if let Some(summary) = summary {
+                sender.send(summary, None, chat_ids.clone()).await;
+            }
+            sender.send(msg, parse_mode, chat_ids).await;
         });
     }
 }
 
+/// -------------------- 控制 bot --------------------
+
+/// 运行时可通过控制 bot 调整的共享状态，与 `TelegramLayer` 持有相同的 `Arc`
+#[derive(Clone)]
+struct ControlState {
+    tag: Arc<Mutex<Vec<String>>>,
+    level_filter: Arc<ArcSwap<LevelFilter>>,
+    muted_until: Arc<Mutex<Option<Instant>>>,
+    ring: Arc<Mutex<VecDeque<String>>>,
+}
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase")]
+enum ControlCommand {
+    #[command(description = "回放最近 N 行日志，默认 20 行")]
+    Tail(String),
+    #[command(description = "调整最低转发级别: off|error|warn|info|debug|trace")]
+    Level(String),
+    #[command(description = "临时静音 N 秒")]
+    Mute(String),
+    #[command(description = "取消静音")]
+    Unmute,
+    #[command(description = "运行时增删 tag 过滤: /tag add|remove <text>")]
+    Tag(String),
+}
+
+async fn handle_control_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    command: ControlCommand,
+    state: &ControlState,
+) {
+    let reply = match command {
+        ControlCommand::Tail(arg) => {
+            let n: usize = arg.trim().parse().unwrap_or(20);
+            let ring = state.ring.lock().unwrap();
+            let lines: Vec<String> = ring.iter().rev().take(n.max(1)).rev().cloned().collect();
+            if lines.is_empty() {
+                "(暂无已记录的日志)".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        ControlCommand::Level(arg) => match arg.trim().parse::<LevelFilter>() {
+            Ok(filter) => {
+                state.level_filter.store(Arc::new(filter));
+                format!("最低转发级别已设置为 {}", filter)
+            }
+            Err(_) => "无法识别的级别，请使用 off|error|warn|info|debug|trace".to_string(),
+        },
+        ControlCommand::Mute(arg) => {
+            let secs: u64 = arg.trim().parse().unwrap_or(60);
+            *state.muted_until.lock().unwrap() = Some(Instant::now() + Duration::from_secs(secs));
+            format!("已静音 {} 秒", secs)
+        }
+        ControlCommand::Unmute => {
+            *state.muted_until.lock().unwrap() = None;
+            "已取消静音".to_string()
+        }
+        ControlCommand::Tag(arg) => {
+            let mut parts = arg.trim().splitn(2, ' ');
+            let action = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            let mut tag = state.tag.lock().unwrap();
+            match action {
+                "add" if !value.is_empty() => {
+                    tag.push(value.to_string());
+                    format!("已添加 tag: {}", value)
+                }
+                "remove" if !value.is_empty() => {
+                    tag.retain(|t| t != value);
+                    format!("已移除 tag: {}", value)
+                }
+                _ => "用法: /tag add|remove <text>".to_string(),
+            }
+        }
+    };
+
+    let _ = bot.send_message(chat_id, reply).await;
+}
+
+/// 长轮询处理来自已配置 chat_ids 的控制命令，拒绝其余来源的消息
+async fn run_control_bot(bot: Arc<Bot>, authorized: Vec<i64>, state: ControlState) {
+    let mut offset = 0i32;
+    loop {
+        let updates = match bot.get_updates().offset(offset).timeout(30).await {
+            Ok(updates) => updates,
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        for update in updates {
+            // update.id 是 u32，offset 要求 i32；正常情况下远不会越界，
+            // 但一旦真的越界也钳到 i32::MAX 而不是静默回绕成负数
+            offset = i32::try_from(update.id.0)
+                .unwrap_or(i32::MAX)
+                .saturating_add(1);
+            let teloxide::types::UpdateKind::Message(message) = &update.kind else {
+                continue;
+            };
+            if !authorized.contains(&message.chat.id.0) {
+                continue;
+            }
+            let Some(text) = message.text() else {
+                continue;
+            };
+            if let Ok(command) = ControlCommand::parse(text, "tracing_telegram") {
+                handle_control_command(&bot, message.chat.id, command, &state).await;
+            }
+        }
+    }
+}
+
 /// -------------------- Builder --------------------
 #[derive(Default)]
 pub struct TelegramLayerBuilder {
@@ -194,6 +854,11 @@ pub struct TelegramLayerBuilder {
     format: Option<TelegramFormat>,
     tag: Option<Vec<String>>,
     unknown: Option<String>,
+    buffer_path: Option<PathBuf>,
+    batch_window: Option<Duration>,
+    routes: HashMap<Level, Vec<i64>>,
+    dedupe_window: Option<Duration>,
+    enable_commands: bool,
 }
 
 impl TelegramLayerBuilder {
@@ -251,17 +916,261 @@ impl TelegramLayerBuilder {
         self
     }
 
+    /// 设置崩溃安全队列的预写日志文件路径。设置后，消息会先追加写入该文件再投递，
+    /// 发送失败时按指数退避原地重试，连续失败 `MAX_DELIVERY_ATTEMPTS` 次后丢弃该批；
+    /// 进程重启时会重放文件中尚未送达的消息
+    pub fn buffer_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.buffer_path = Some(path.into());
+        self
+    }
+
+    /// 在该时间窗口内到达的多条日志会被合并成一条消息发送，避免触发 Telegram 的限流
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = Some(window);
+        self
+    }
+
+    /// 将指定级别的日志路由到一组独立的 chat，例如把 ERROR/WARN 发到值班群，
+    /// INFO/DEBUG 发到归档频道；未配置路由的级别回退到默认的 `chat_ids`
+    pub fn route(mut self, level: Level, chat_ids: Vec<i64>) -> Self {
+        self.routes.insert(level, chat_ids);
+        self
+    }
+
+    /// 在该时间窗口内重复出现的相同消息只计数不发送，窗口结束后（该消息再次出现时，
+    /// 或由按窗口周期运行的清理任务）补发一条「重复了 N 次」的汇总，用于抑制错误风暴刷屏；
+    /// 遇到不同的消息不会提前补发汇总
+    pub fn dedupe_window(mut self, window: Duration) -> Self {
+        self.dedupe_window = Some(window);
+        self
+    }
+
+    /// 启用双向控制 bot：已配置的 chat（`chat_ids` 与 `route` 涉及的所有 chat）
+    /// 可以通过 `/tail`、`/level`、`/mute`、`/unmute`、`/tag` 命令查询最近日志
+    /// 或调整运行时过滤，其余 chat 发来的命令会被忽略
+    pub fn with_commands(mut self) -> Self {
+        self.enable_commands = true;
+        self
+    }
+
     pub fn build(self) -> TelegramLayer {
         let bot = self.bot.expect("Bot must be set");
         let chat_ids = self.chat_ids.expect("chat_id must be set");
         let format = self.format.unwrap_or(TelegramFormat::Text);
         let unknown = self.unknown.unwrap_or("Unknown".to_string());
 
+        let tag = Arc::new(Mutex::new(self.tag.unwrap_or_default()));
+        let level_filter = Arc::new(ArcSwap::new(Arc::new(LevelFilter::TRACE)));
+        let muted_until = Arc::new(Mutex::new(None));
+        let ring = Arc::new(Mutex::new(VecDeque::with_capacity(RING_CAPACITY)));
+
+        if self.enable_commands {
+            let mut authorized = chat_ids.clone();
+            authorized.extend(self.routes.values().flatten().copied());
+            authorized.sort_unstable();
+            authorized.dedup();
+
+            let state = ControlState {
+                tag: tag.clone(),
+                level_filter: level_filter.clone(),
+                muted_until: muted_until.clone(),
+                ring: ring.clone(),
+            };
+            tokio::spawn(run_control_bot(bot.clone(), authorized, state));
+        }
+
+        let sender = TelegramSender::new(bot, self.buffer_path, self.batch_window);
+        let dedupe = self.dedupe_window.map(|window| Arc::new(Dedupe::new(window)));
+
+        // 周期性地把那些窗口已过、但对应消息再也没有复现、因而没有机会补发
+        // 汇总的抑制计数冲刷出去，否则一次不再重现的错误风暴会被悄悄遗忘
+        if let (Some(dedupe), Some(window)) = (&dedupe, self.dedupe_window) {
+            let dedupe = dedupe.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(window);
+                loop {
+                    interval.tick().await;
+                    for (summary, chat_ids) in dedupe.sweep_expired() {
+                        sender.send(summary, None, chat_ids).await;
+                    }
+                }
+            });
+        }
+
         TelegramLayer {
-            sender: TelegramSender::new(bot, chat_ids),
+            sender,
             format,
-            tag: self.tag.unwrap_or(vec![]),
+            tag,
             unknown,
+            default_chat_ids: chat_ids,
+            routes: self.routes,
+            dedupe,
+            level_filter,
+            muted_until,
+            ring,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// 每个测试用例一个独立的临时文件路径，避免并行测试互相踩踏
+    fn temp_wal_path(tag: &str) -> PathBuf {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!(
+            "tracing_telegram_test_wal_{}_{}_{}.jsonl",
+            std::process::id(),
+            tag,
+            n
+        ))
+    }
+
+    #[test]
+    fn wal_round_trip_preserves_entries() {
+        let path = temp_wal_path("round_trip");
+        let _ = std::fs::remove_file(&path);
+
+        let first = QueuedMessage {
+            msg: "hello".to_string(),
+            parse_mode: None,
+            chat_ids: vec![1, 2],
+        };
+        let second = QueuedMessage {
+            msg: "world".to_string(),
+            parse_mode: Some(teloxide::types::ParseMode::MarkdownV2),
+            chat_ids: vec![3],
+        };
+        append_wal(&path, &first);
+        append_wal(&path, &second);
+
+        let loaded = load_wal(&path);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].msg, "hello");
+        assert_eq!(loaded[0].chat_ids, vec![1, 2]);
+        assert!(loaded[0].parse_mode.is_none());
+        assert_eq!(loaded[1].msg, "world");
+        assert_eq!(loaded[1].chat_ids, vec![3]);
+        assert!(matches!(
+            loaded[1].parse_mode,
+            Some(teloxide::types::ParseMode::MarkdownV2)
+        ));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn wal_rewrite_drops_already_delivered_entries() {
+        let path = temp_wal_path("rewrite");
+        let _ = std::fs::remove_file(&path);
+
+        let delivered = QueuedMessage {
+            msg: "delivered".to_string(),
+            parse_mode: None,
+            chat_ids: vec![1],
+        };
+        let still_pending = QueuedMessage {
+            msg: "still pending".to_string(),
+            parse_mode: None,
+            chat_ids: vec![1],
+        };
+        append_wal(&path, &delivered);
+        append_wal(&path, &still_pending);
+
+        let remaining: VecDeque<QueuedMessage> = VecDeque::from([QueuedMessage {
+            msg: "still pending".to_string(),
+            parse_mode: None,
+            chat_ids: vec![1],
+        }]);
+        rewrite_wal(&path, &remaining);
+
+        let loaded = load_wal(&path);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].msg, "still pending");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn load_wal_on_missing_file_is_an_empty_queue() {
+        let path = temp_wal_path("missing");
+        let _ = std::fs::remove_file(&path);
+        assert!(load_wal(&path).is_empty());
+    }
+
+    #[test]
+    fn dedupe_suppresses_within_window_and_reports_count_afterwards() {
+        let dedupe = Dedupe::new(Duration::from_millis(50));
+        let chat_ids = vec![42];
+
+        assert!(matches!(
+            dedupe.check("boom", &chat_ids),
+            DedupeDecision::Send(None)
+        ));
+        assert!(matches!(
+            dedupe.check("boom", &chat_ids),
+            DedupeDecision::Suppress
+        ));
+        assert!(matches!(
+            dedupe.check("boom", &chat_ids),
+            DedupeDecision::Suppress
+        ));
+
+        std::thread::sleep(Duration::from_millis(60));
+
+        let decision = dedupe.check("boom", &chat_ids);
+        let DedupeDecision::Send(Some(summary)) = decision else {
+            panic!("expected a repeated-count summary after the dedupe window elapsed");
+        };
+        assert!(summary.contains('2'));
+    }
+
+    #[test]
+    fn dedupe_suppresses_repeated_identical_key() {
+        // 回归测试：去重键一度是完整格式化过的消息（含每秒变化的时间戳），
+        // 导致同一条错误哪怕只隔一秒出现也会被当成不同消息，从未真正抑制过。
+        // 现在 on_event 只用消息正文和字段作键；这里检查同一个键第二次出现即被抑制
+        let dedupe = Dedupe::new(Duration::from_secs(30));
+        let key = "same content, different timestamps would defeat dedupe";
+
+        assert!(matches!(
+            dedupe.check(key, &[1]),
+            DedupeDecision::Send(None)
+        ));
+        assert!(matches!(dedupe.check(key, &[1]), DedupeDecision::Suppress));
+    }
+
+    #[test]
+    fn dedupe_sweep_expired_flushes_bursts_that_never_recur() {
+        let dedupe = Dedupe::new(Duration::from_millis(30));
+        let chat_ids = vec![7, 8];
+
+        dedupe.check("flaky", &chat_ids);
+        dedupe.check("flaky", &chat_ids);
+        dedupe.check("flaky", &chat_ids);
+
+        std::thread::sleep(Duration::from_millis(40));
+
+        let flushed = dedupe.sweep_expired();
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].1, chat_ids);
+        assert!(flushed[0].0.contains('2'));
+
+        // 冲刷过的条目已经被清理，不会重复上报
+        assert!(dedupe.sweep_expired().is_empty());
+    }
+
+    #[test]
+    fn dedupe_sweep_expired_ignores_entries_never_suppressed() {
+        let dedupe = Dedupe::new(Duration::from_millis(20));
+        dedupe.check("only-once", &[1]);
+
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(dedupe.sweep_expired().is_empty());
+    }
+}